@@ -0,0 +1,101 @@
+//! A structured error type for the SOCKS handshake.
+//!
+//! The message parsers and the connection state machine used to signal malformed input with
+//! `io::Error::new(InvalidData, "some string")`, which threw away the very information needed to
+//! pick a correct wire-level reply. `SocksError` keeps that information: each protocol variant
+//! records the offending octet (or version), and `SocksError::reply` maps it onto the RFC 1928
+//! `Reply` that `Connection::ready` sends back when a request fails, before closing. Underlying
+//! transport failures are carried through unchanged in the `Io` variant.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use rfc1928::{Reply, SocksVersion};
+
+/// The result of any operation that may fail to interpret a SOCKS message. Mirrors the crate's
+/// `Result<Option<T>>` parsing convention, but with a typed error in place of `io::Error`.
+pub type Result<T> = ::std::result::Result<T, SocksError>;
+
+pub enum SocksError {
+    /// The leading version octet names no SOCKS version this server recognises.
+    UnknownVersion(u8),
+    /// A recognised but unsupported SOCKS version was requested.
+    UnsupportedVersion(SocksVersion),
+    /// A method-selection message advertised zero methods, which RFC 1928 forbids.
+    ZeroMethods,
+    /// An advertised authentication method octet names no known method.
+    UnknownAuthMethod(u8),
+    /// A request named a command octet outside `CONNECT`/`BIND`/`UDP`.
+    UnknownCommand(u8),
+    /// A request named an address-type octet outside `IPv4`/`DOMAINNAME`/`IPv6`.
+    BadAddressType(u8),
+    /// A reserved octet that RFC 1928 requires to be `0x00` held some other value.
+    BadReserved(u8),
+    /// An underlying transport error.
+    Io(io::Error),
+}
+
+impl SocksError {
+    /// Maps this error onto the RFC 1928 reply that best describes it, so a failed handshake can
+    /// answer the client with a meaningful code rather than closing silently.
+    pub fn reply(&self) -> Reply {
+        use self::SocksError::*;
+        match *self {
+            UnknownCommand(_)  => Reply::BADCMND,
+            BadAddressType(_)  => Reply::BADADDR,
+            _                  => Reply::FAIL,
+        }
+    }
+}
+
+impl From<io::Error> for SocksError {
+    fn from(e: io::Error) -> SocksError {
+        SocksError::Io(e)
+    }
+}
+
+impl fmt::Display for SocksError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SocksError::*;
+        match *self {
+            UnknownVersion(v)     => write!(f, "unknown socks version: {:#04x}", v),
+            UnsupportedVersion(v) => write!(f, "unsupported socks version: {}", v.desc()),
+            ZeroMethods          => write!(f, "`nmethods` cannot be 0"),
+            UnknownAuthMethod(m)  => write!(f, "unknown auth method: {:#04x}", m),
+            UnknownCommand(c)     => write!(f, "unknown command: {:#04x}", c),
+            BadAddressType(a)     => write!(f, "unknown address type: {:#04x}", a),
+            BadReserved(r)        => write!(f, "reserved octet must be zero, got {:#04x}", r),
+            Io(ref e)             => write!(f, "{}", e),
+        }
+    }
+}
+
+impl fmt::Debug for SocksError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Error for SocksError {
+    fn description(&self) -> &str {
+        use self::SocksError::*;
+        match *self {
+            UnknownVersion(_)     => "unknown socks version",
+            UnsupportedVersion(_) => "unsupported socks version",
+            ZeroMethods          => "`nmethods` cannot be 0",
+            UnknownAuthMethod(_)  => "unknown auth method",
+            UnknownCommand(_)     => "unknown command",
+            BadAddressType(_)     => "unknown address type",
+            BadReserved(_)        => "reserved octet must be zero",
+            Io(ref e)             => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            SocksError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -13,11 +13,321 @@
 //! Only in the "normal" case, were the buffer can be interpreted as a valid message, an
 //! `Ok(Some(_))` is returned.
 
-use std::io::{Error, Result};
+use std::io::Error as IoError;
 use std::io::ErrorKind::{InvalidData};
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::slice::Iter;
 
-use rfc1928::{AuthMethod, SocksVersion};
+use error::{Result, SocksError};
+use rfc1928::{AddressType, AuthMethod, Command, SocksVersion};
+
+/// The version octet which prefixes every RFC 1929 username/password sub-negotiation message.
+const USERPASS_VERSION: u8 = 0x01;
+
+/// A parsed RFC 1929 username/password request, sent by the client during `MethodNegotiation`
+/// once `AuthMethod::PASSWD` has been selected.
+pub struct UserPassMessage {
+    pub username: String,
+    pub password: String,
+}
+
+impl UserPassMessage {
+
+    /// Tries to deserialize the first octets of `buf` as an RFC 1929 request.
+    ///
+    /// The wire format is one version octet (`0x01`), a one-octet `ULEN`, `ULEN` username bytes,
+    /// a one-octet `PLEN`, and `PLEN` password bytes. The usual incremental-parse contract holds:
+    /// `Ok(None)` until the full message has arrived, `Err` on a bad version octet.
+    pub fn try_new(buf: &Vec<u8>) -> Result<Option<UserPassMessage>> {
+        let version = match buf.get(0) {
+            None    => return Ok(None),
+            Some(v) => *v,
+        };
+        if version != USERPASS_VERSION {
+            return Err(SocksError::Io(IoError::new(InvalidData,
+                                                   "unknown username/password auth version")));
+        }
+
+        let ulen = match buf.get(1) {
+            None    => return Ok(None),
+            Some(n) => *n as usize,
+        };
+        let plen_idx = 2 + ulen;
+        let plen = match buf.get(plen_idx) {
+            None    => return Ok(None),
+            Some(n) => *n as usize,
+        };
+        let end = plen_idx + 1 + plen;
+        if buf.len() < end {
+            return Ok(None);
+        }
+
+        let username = try!(Self::field(&buf[2 .. plen_idx]));
+        let password = try!(Self::field(&buf[plen_idx + 1 .. end]));
+        Ok(Some(UserPassMessage { username: username, password: password }))
+    }
+
+    /// Interprets a credential field as UTF-8, rejecting malformed bytes.
+    fn field(bytes: &[u8]) -> Result<String> {
+        match String::from_utf8(bytes.to_vec()) {
+            Ok(s)  => Ok(s),
+            Err(_) => Err(SocksError::Io(IoError::new(InvalidData,
+                                                      "credential field is not valid UTF-8"))),
+        }
+    }
+}
+
+/// The destination carried by a SOCKS 5 request, decoded according to its `ATYP` octet.
+pub enum DestAddr {
+    IPv4(Ipv4Addr),
+    IPv6(Ipv6Addr),
+    DomainName(String),
+}
+
+/// A parsed SOCKS 5 relay request: the `CMD` the client wishes to perform and the destination it
+/// names. Produced by `State::ReadRequest` and consumed by the relay subsystems.
+pub struct RequestMessage {
+    pub version: SocksVersion,
+    pub command: Command,
+    pub dest: DestAddr,
+    pub port: u16,
+}
+
+impl RequestMessage {
+
+    /// Tries to deserialize the first octets of `buf` as an RFC 1928 relay request.
+    ///
+    /// The layout is VER, CMD, RSV (`0x00`), ATYP, a variable-length address, and a two-octet
+    /// big-endian port. The incremental-parse contract holds: `Ok(None)` until the full
+    /// variable-length message has arrived, `Err` on a bad RSV octet, an unknown ATYP, or an
+    /// unknown CMD.
+    pub fn try_new(buf: &Vec<u8>) -> Result<Option<RequestMessage>> {
+        // VER, CMD, RSV, ATYP: the fixed-size prefix present on every request.
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let version = match SocksVersion::from_u8(buf[0]) {
+            None    => return Err(SocksError::UnknownVersion(buf[0])),
+            Some(v) => v,
+        };
+        let command = match Command::from_u8(buf[1]) {
+            None    => return Err(SocksError::UnknownCommand(buf[1])),
+            Some(c) => c,
+        };
+        if buf[2] != 0x00 {
+            return Err(SocksError::BadReserved(buf[2]));
+        }
+        let atyp = match AddressType::from_u8(buf[3]) {
+            None    => return Err(SocksError::BadAddressType(buf[3])),
+            Some(a) => a,
+        };
+
+        // Decode the variable-length address, tracking the offset at which the port begins.
+        let (dest, port_at) = match atyp {
+            AddressType::IPv4 => {
+                if buf.len() < 4 + 4 {
+                    return Ok(None);
+                }
+                let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+                (DestAddr::IPv4(ip), 8)
+            }
+            AddressType::IPv6 => {
+                if buf.len() < 4 + 16 {
+                    return Ok(None);
+                }
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&buf[4 .. 20]);
+                (DestAddr::IPv6(Ipv6Addr::from(octets)), 20)
+            }
+            AddressType::DOMAINNAME(_) => {
+                // A domain name is prefixed by a one-octet length giving the number of name bytes.
+                let len = match buf.get(4) {
+                    None    => return Ok(None),
+                    Some(n) => *n as usize,
+                };
+                let name_end = 5 + len;
+                if buf.len() < name_end {
+                    return Ok(None);
+                }
+                let name = match String::from_utf8(buf[5 .. name_end].to_vec()) {
+                    Ok(s)  => s,
+                    Err(_) => return Err(SocksError::Io(IoError::new(InvalidData,
+                                                                     "domain name is not valid UTF-8"))),
+                };
+                (DestAddr::DomainName(name), name_end)
+            }
+        };
+
+        if buf.len() < port_at + 2 {
+            return Ok(None);
+        }
+        let port = ((buf[port_at] as u16) << 8) | (buf[port_at + 1] as u16);
+
+        Ok(Some(RequestMessage { version: version, command: command, dest: dest, port: port }))
+    }
+
+    /// Tries to deserialize the first octets of `buf` as a SOCKS 4 (or SOCKS 4a) request, producing
+    /// the same `RequestMessage` representation used for RFC 1928 so the CONNECT relay can serve
+    /// both versions.
+    ///
+    /// The layout is VN, CD, a two-octet big-endian port, a four-octet IPv4 address, and a
+    /// NUL-terminated user id. An address of `0.0.0.x` (`x != 0`) is the SOCKS 4a convention
+    /// signalling that a second NUL-terminated field, the destination host name, follows the user
+    /// id. The incremental-parse contract holds: `Ok(None)` until both NUL terminators have
+    /// arrived, `Err` on an unknown CD.
+    pub fn try_socks4(buf: &Vec<u8>) -> Result<Option<RequestMessage>> {
+        // VN, CD, DSTPORT, DSTIP: the fixed-size prefix. VN is validated by the caller.
+        if buf.len() < 8 {
+            return Ok(None);
+        }
+        // SOCKS 4 defines only CONNECT and BIND; `0x03` (UDP ASSOCIATE) is a SOCKS 5 command.
+        let command = match Command::from_u8(buf[1]) {
+            None | Some(Command::UDP) => return Err(SocksError::UnknownCommand(buf[1])),
+            Some(c) => c,
+        };
+        let port = ((buf[2] as u16) << 8) | (buf[3] as u16);
+        let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+
+        // USERID is a NUL-terminated string beginning at octet 8.
+        let userid_end = match buf[8 ..].iter().position(|&b| b == 0x00) {
+            None    => return Ok(None),  // Wait for the terminating NUL.
+            Some(i) => 8 + i,
+        };
+
+        let octets = ip.octets();
+        let is_socks4a = octets[0] == 0 && octets[1] == 0 && octets[2] == 0 && octets[3] != 0;
+        let dest = if is_socks4a {
+            let name_start = userid_end + 1;
+            let name_end = match buf[name_start ..].iter().position(|&b| b == 0x00) {
+                None    => return Ok(None),
+                Some(i) => name_start + i,
+            };
+            match String::from_utf8(buf[name_start .. name_end].to_vec()) {
+                Ok(s)  => DestAddr::DomainName(s),
+                Err(_) => return Err(SocksError::Io(IoError::new(InvalidData,
+                                                                 "domain name is not valid UTF-8"))),
+            }
+        } else {
+            DestAddr::IPv4(ip)
+        };
+
+        Ok(Some(RequestMessage {
+            version: SocksVersion::SOCKS4,
+            command: command,
+            dest: dest,
+            port: port,
+        }))
+    }
+}
+
+/// A decoded SOCKS 5 UDP request header together with the offset at which its payload begins.
+///
+/// The wire format (RFC 1928 §7) is a two-octet reserved field (`0x0000`), a one-octet `FRAG`
+/// number, an `ATYP` octet, the destination address, and a two-octet big-endian port, followed by
+/// the user payload.
+pub struct UdpRequest {
+    pub frag: u8,
+    pub dest: DestAddr,
+    pub port: u16,
+    /// The index into the datagram at which the user payload begins.
+    pub header_len: usize,
+}
+
+impl UdpRequest {
+
+    /// Tries to decode the header of a client datagram. Unlike the stream parsers this operates on
+    /// a single datagram, so a short buffer is a malformed header rather than a "wait for more"
+    /// condition; `Ok(None)` is therefore never returned, but the signature mirrors the crate's
+    /// `Result<Option<T>>` convention for uniformity at the call sites.
+    pub fn try_new(buf: &[u8]) -> Result<Option<UdpRequest>> {
+        if buf.len() < 4 {
+            return Err(SocksError::Io(IoError::new(InvalidData,
+                                                   "udp datagram shorter than its header")));
+        }
+        if buf[0] != 0x00 {
+            return Err(SocksError::BadReserved(buf[0]));
+        }
+        if buf[1] != 0x00 {
+            return Err(SocksError::BadReserved(buf[1]));
+        }
+        let frag = buf[2];
+        if frag != 0x00 {
+            return Err(SocksError::Io(IoError::new(InvalidData,
+                                                   "fragmented datagrams are not supported")));
+        }
+        let atyp = match AddressType::from_u8(buf[3]) {
+            None    => return Err(SocksError::BadAddressType(buf[3])),
+            Some(a) => a,
+        };
+        let (dest, port_at) = match atyp {
+            AddressType::IPv4 => {
+                if buf.len() < 4 + 4 {
+                    return Err(SocksError::Io(IoError::new(InvalidData,
+                                                           "datagram truncated in IPv4 address")));
+                }
+                (DestAddr::IPv4(Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7])), 8)
+            }
+            AddressType::IPv6 => {
+                if buf.len() < 4 + 16 {
+                    return Err(SocksError::Io(IoError::new(InvalidData,
+                                                           "datagram truncated in IPv6 address")));
+                }
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&buf[4 .. 20]);
+                (DestAddr::IPv6(Ipv6Addr::from(octets)), 20)
+            }
+            AddressType::DOMAINNAME(_) => {
+                let len = match buf.get(4) {
+                    None    => return Err(SocksError::Io(IoError::new(InvalidData,
+                                                                      "datagram truncated in domain name"))),
+                    Some(n) => *n as usize,
+                };
+                let name_end = 5 + len;
+                if buf.len() < name_end {
+                    return Err(SocksError::Io(IoError::new(InvalidData,
+                                                           "datagram truncated in domain name")));
+                }
+                let name = match String::from_utf8(buf[5 .. name_end].to_vec()) {
+                    Ok(s)  => s,
+                    Err(_) => return Err(SocksError::Io(IoError::new(InvalidData,
+                                                                     "domain name is not valid UTF-8"))),
+                };
+                (DestAddr::DomainName(name), name_end)
+            }
+        };
+        if buf.len() < port_at + 2 {
+            return Err(SocksError::Io(IoError::new(InvalidData, "datagram truncated in port")));
+        }
+        let port = ((buf[port_at] as u16) << 8) | (buf[port_at + 1] as u16);
+
+        Ok(Some(UdpRequest { frag: frag, dest: dest, port: port, header_len: port_at + 2 }))
+    }
+}
+
+/// Wraps a reply datagram received from `src` in the RFC 1928 UDP header expected by the client.
+pub fn encode_udp_reply(src: &::std::net::SocketAddr, payload: &[u8]) -> Vec<u8> {
+    use std::net::SocketAddr;
+    let mut out = Vec::with_capacity(payload.len() + 22);
+    out.push(0x00);  // RSV
+    out.push(0x00);  // RSV
+    out.push(0x00);  // FRAG
+    match *src {
+        SocketAddr::V4(v4) => {
+            out.push(0x01);  // ATYP: IPv4
+            out.extend(v4.ip().octets().iter().cloned());
+            out.push((v4.port() >> 8) as u8);
+            out.push(v4.port() as u8);
+        }
+        SocketAddr::V6(v6) => {
+            out.push(0x04);  // ATYP: IPv6
+            out.extend(v6.ip().octets().iter().cloned());
+            out.push((v6.port() >> 8) as u8);
+            out.push(v6.port() as u8);
+        }
+    }
+    out.extend(payload.iter().cloned());
+    out
+}
 
 struct AuthMethodsMessage {
     version: SocksVersion,
@@ -68,11 +378,11 @@ impl AuthMethodsMessage {
             Some(v) => *v,
         };
         let version = match SocksVersion::from_u8(version) {
-            None    => return Err(Error::new(InvalidData, "unknown socks version")),
+            None    => return Err(SocksError::UnknownVersion(version)),
             Some(v) => v,
         };
         match version {
-            SOCKS4 => Err(Error::new(InvalidData, "SOCKS v4 not supported")),
+            SOCKS4 => Err(SocksError::UnsupportedVersion(SOCKS4)),
             SOCKS5 => Ok(Some(SOCKS5)),
         }
     }
@@ -84,7 +394,7 @@ impl AuthMethodsMessage {
             Some(n) => *n,
         };
         match nmethods {
-            0 => Err(Error::new(InvalidData, "`nmethods` cannot be 0")),
+            0 => Err(SocksError::ZeroMethods),
             n => Ok(Some(n)),
         }
     }
@@ -113,7 +423,7 @@ impl AuthMethodsMessage {
     #[inline]
     fn try_add_method(&mut self, method_id: u8) -> Result<()> {
         let method = match AuthMethod::from_u8(method_id) {
-            None    => return Err(Error::new(InvalidData, "unknown auth method")),
+            None    => return Err(SocksError::UnknownAuthMethod(method_id)),
             Some(m) => m,
         };
         self.add(method);
@@ -6,6 +6,7 @@
 //! from NEC's SOCKS5 reference implementation. Some rust-specific style choices were inspired by
 //! `carllerche/nix-rust`.
 
+#[derive(Debug, Clone, Copy)]
 pub enum SocksVersion {
     SOCKS4 = 0x04,
     SOCKS5 = 0x05,
@@ -30,7 +31,7 @@ impl SocksVersion {
 }
 
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Clone, Copy)]
 pub enum AuthMethod {
     NONE = 0x00,
     GSSAPI = 0x01,
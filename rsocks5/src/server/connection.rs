@@ -15,27 +15,576 @@
 
 #![allow(dead_code)]
 
-use std::io::{Error, Result};
-use std::io::ErrorKind::{InvalidData};
+use std::io::{Error, Read, Result as IoResult, Write};
+use std::io::ErrorKind::WouldBlock;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
-use rfc1928::{AuthMethod, SocksVersion};
+use mio::{EventLoop, EventSet, PollOpt, Token};
+use mio::tcp::TcpStream;
+use mio::udp::UdpSocket;
+
+use error::{Result, SocksError};
+use messages::{DestAddr, RequestMessage, UdpRequest, UserPassMessage, encode_udp_reply};
+use rfc1928::{AuthMethod, Command, Reply, SocksVersion};
+use super::{Credentials, SocksServer};
 
 
 /// This is currently set to the size needed to hold a full read request with `AddressType::IPv6`.
 /// The buffer may need to grow in certain cases (e.g. with a long `AddressType::DOMAINNAME`).
 const INITIAL_BUF_SIZE: usize = 22;
 
+/// The size of the chunk read off of a relayed socket in a single `read()` during `State::Relay`.
+const RELAY_CHUNK_SIZE: usize = 4096;
+
+/// Outbound (peer) sockets are registered under the owning connection's token plus this offset, so
+/// that `SocksServer` can route a peer readiness back to its `Connection` by subtracting it again
+/// without maintaining a separate peer-token map.
+pub const PEER_TOKEN_OFFSET: usize = 1 << 20;
+
+/// The relay socket of a UDP association is registered under the owning control connection's token
+/// plus this offset, by the same routing scheme as `PEER_TOKEN_OFFSET`.
+pub const UDP_TOKEN_OFFSET: usize = 2 << 20;
+
+/// The largest datagram the UDP relay will buffer in a single `recv_from`.
+const UDP_DATAGRAM_SIZE: usize = 65_535;
+
 
 pub struct Connection {
+    /// The token identifying the client half of this connection in the event loop.
+    token: Token,
+    /// The socket on which the client spoke the SOCKS handshake.
+    client: TcpStream,
+    /// The outbound socket opened on the client's behalf once a `CONNECT` request is served.
+    peer: Option<TcpStream>,
+    /// The token under which `peer` is registered; the event loop uses it to route readiness
+    /// back to this same logical `Connection`.
+    peer_token: Option<Token>,
     buf: Vec<u8>,
     state: State,
+    /// The credentials accepted during username/password sub-negotiation. An empty store means no
+    /// authentication is configured and `AuthMethod::NONE` is offered.
+    credentials: Credentials,
+
+    /// Bytes read from the client and awaiting a write towards the peer.
+    to_peer: Vec<u8>,
+    /// Bytes read from the peer and awaiting a write towards the client.
+    to_client: Vec<u8>,
+    /// Set once the client half has signalled EOF; the opposite direction is still flushed.
+    client_done: bool,
+    /// Set once the peer half has signalled EOF.
+    peer_done: bool,
+
+    /// The relay socket of an active UDP association, if the client issued `UDP ASSOCIATE`. It is
+    /// torn down when this control connection closes (RFC 1928 §7).
+    udp: Option<UdpSocket>,
+    /// The token under which `udp` is registered.
+    udp_token: Option<Token>,
+    /// The client's datagram source address, learned from its first relayed datagram. Datagrams
+    /// arriving from this address are forwarded outward; all others are wrapped back to it.
+    udp_client: Option<SocketAddr>,
+
+    /// Set once the client has been identified as a SOCKS 4/4a peer, so that replies use the
+    /// 8-octet v4 format rather than the RFC 1928 one.
+    socks4: bool,
 }
 
 impl Connection {
-    pub fn new() -> Connection {
+    pub fn new(token: Token, client: TcpStream, credentials: Credentials) -> Connection {
         Connection {
-            buf:   Vec::with_capacity(INITIAL_BUF_SIZE),
-            state: State::ReadMethods,
+            token:      token,
+            client:     client,
+            peer:       None,
+            peer_token: None,
+            buf:        Vec::with_capacity(INITIAL_BUF_SIZE),
+            state:      State::ReadMethods,
+            credentials: credentials,
+            to_peer:    Vec::new(),
+            to_client:  Vec::new(),
+            client_done: false,
+            peer_done:   false,
+            udp:        None,
+            udp_token:  None,
+            udp_client: None,
+            socks4:     false,
+        }
+    }
+
+    /// The socket on which this connection first accepted the client.
+    pub fn client(&self) -> &TcpStream {
+        &self.client
+    }
+
+    /// The outbound socket, once a relay target has been opened.
+    pub fn peer(&self) -> Option<&TcpStream> {
+        self.peer.as_ref()
+    }
+
+    /// `true` once the connection has reached `State::Closed` (after a finished relay, a failed or
+    /// rejected handshake, or the end of a UDP association) and its sockets may be dropped.
+    pub fn is_closed(&self) -> bool {
+        match self.state {
+            State::Closed => true,
+            _ => false,
+        }
+    }
+
+    /// Dispatches an event-loop readiness notification for the client token, the peer token or the
+    /// UDP relay token to the appropriate stage of the handshake or relay.
+    pub fn ready(&mut self, event_loop: &mut EventLoop<SocksServer>, token: Token, events: EventSet) {
+        let result = match self.state {
+            State::UdpAssociate => {
+                if Some(token) == self.udp_token {
+                    self.relay_udp()
+                } else {
+                    // The association (and its relay socket) ends when the client closes the
+                    // control connection, which a read reaching EOF reveals.
+                    let mut closed = false;
+                    let mut discard = Vec::new();
+                    let read = pump_read(&mut self.client, &mut discard, &mut closed);
+                    if closed {
+                        self.state = State::Closed;
+                    }
+                    read.map_err(SocksError::from)
+                }
+            }
+            State::Connecting => {
+                if Some(token) == self.peer_token && events.is_writable() {
+                    self.finish_connect()
+                } else {
+                    Ok(())
+                }
+            }
+            State::Relay => self.relay(event_loop, token, events),
+            _ => self.handshake(event_loop, events),
+        };
+        if let Err(e) = result {
+            info!("tearing down connection {:?}: {}", self.token, e);
+            match self.state {
+                // A request-stage failure owes the client a reply in its version's framing
+                // (`write_reply` picks RFC 1928 or the 8-octet SOCKS 4 form).
+                State::ReadRequest | State::Connecting => {
+                    let _ = self.write_reply(e.reply(), unspecified_addr());
+                }
+                // A SOCKS 4/4a client whose request failed to parse gets a v4 rejection.
+                State::ReadMethods if self.socks4 => {
+                    let _ = self.write_reply(e.reply(), unspecified_addr());
+                }
+                // The client is still awaiting the 2-byte method-selection reply; answer `0xFF`.
+                State::ReadMethods => {
+                    let _ = self.client.write_all(&[SocksVersion::SOCKS5 as u8, 0xFF]);
+                }
+                // The client is awaiting the 2-byte RFC 1929 status; answer a failure status.
+                State::MethodNegotiation => {
+                    let _ = self.client.write_all(&[0x01, 0x01]);
+                }
+                // A relay-stage failure happens after the reply has been sent; emitting one now
+                // would corrupt the stream, so just close.
+                _ => {}
+            }
+            self.state = State::Closed;
+        }
+    }
+
+    /// Drives the staged SOCKS handshake. Only the client token is readable during these stages,
+    /// so `token` is not consulted here.
+    fn handshake(&mut self, event_loop: &mut EventLoop<SocksServer>, _events: EventSet) -> Result<()> {
+        loop {
+            match self.state {
+                State::ReadMethods => {
+                    try!(self.fill_from_client());
+                    // A leading `0x04` octet is a SOCKS 4/4a client, which sends its relay request
+                    // immediately rather than negotiating an authentication method first.
+                    if let Some(&0x04) = self.buf.get(0) {
+                        // Mark the client as v4 up front so a parse failure is answered with a v4
+                        // reply rather than an RFC 1928 one.
+                        self.socks4 = true;
+                        let request = match try!(RequestMessage::try_socks4(&self.buf)) {
+                            None => return Ok(()),
+                            Some(request) => request,
+                        };
+                        self.buf.clear();
+                        try!(self.serve_request(event_loop, request));
+                        continue;
+                    }
+                    let offered = match try!(AuthMethodsSet::methods(&self.buf)) {
+                        None => return Ok(()),  // Not enough bytes yet; wait for readiness.
+                        Some(set) => set,
+                    };
+                    let method = match self.select_method(&offered) {
+                        Some(method) => method,
+                        None => {
+                            // None of the offered methods are acceptable: reply `0xFF` and close
+                            // the connection, as RFC 1928 §3 requires.
+                            self.buf.clear();
+                            try!(self.client.write_all(&[SocksVersion::SOCKS5 as u8, 0xFF]));
+                            self.state = State::Closed;
+                            return Ok(());
+                        }
+                    };
+                    self.buf.clear();
+                    try!(self.write_method(method));
+                    self.state = match method {
+                        AuthMethod::PASSWD => State::MethodNegotiation,
+                        _ => State::ReadRequest,
+                    };
+                }
+                State::MethodNegotiation => {
+                    try!(self.fill_from_client());
+                    let creds = match try!(UserPassMessage::try_new(&self.buf)) {
+                        None => return Ok(()),
+                        Some(msg) => msg,
+                    };
+                    self.buf.clear();
+                    if self.credentials_match(&creds) {
+                        try!(self.client.write_all(&[0x01, 0x00]));
+                        self.state = State::ReadRequest;
+                    } else {
+                        let _ = self.client.write_all(&[0x01, 0x01]);
+                        self.state = State::Closed;
+                        return Ok(());
+                    }
+                }
+                State::ReadRequest => {
+                    try!(self.fill_from_client());
+                    let request = match try!(RequestMessage::try_new(&self.buf)) {
+                        None => return Ok(()),
+                        Some(request) => request,
+                    };
+                    self.buf.clear();
+                    try!(self.serve_request(event_loop, request));
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Opens the outbound connection requested by the client and registers it in the event loop,
+    /// then enters `State::Connecting` to await the (non-blocking) connect result before replying.
+    fn serve_request(&mut self, event_loop: &mut EventLoop<SocksServer>, request: RequestMessage)
+                     -> Result<()> {
+        match request.command {
+            Command::CONNECT => {},
+            Command::UDP => return self.associate_udp(event_loop),
+            _ => {
+                try!(self.write_reply(Reply::BADCMND, unspecified_addr()));
+                self.state = State::Closed;
+                return Ok(());
+            }
+        }
+
+        let addr = match resolve_dest(&request.dest, request.port) {
+            Some(addr) => addr,
+            None => {
+                try!(self.write_reply(Reply::HOSTUNREACH, unspecified_addr()));
+                self.state = State::Closed;
+                return Ok(());
+            }
+        };
+
+        let peer = match TcpStream::connect(&addr) {
+            Ok(sock) => sock,
+            Err(e) => {
+                try!(self.write_reply(reply_for_connect_error(&e), unspecified_addr()));
+                self.state = State::Closed;
+                return Ok(());
+            }
+        };
+
+        let peer_token = Token(self.token.0 + PEER_TOKEN_OFFSET);
+        try!(event_loop.register(&peer, peer_token, EventSet::readable() | EventSet::writable(),
+                                 PollOpt::edge()));
+        self.peer = Some(peer);
+        self.peer_token = Some(peer_token);
+
+        // The connect is still in flight: `mio`'s `TcpStream::connect` returns before the TCP
+        // handshake completes. Defer the reply until the peer token first signals writability.
+        self.state = State::Connecting;
+        Ok(())
+    }
+
+    /// Completes a pending outbound connect once the peer socket reports writability. A failed
+    /// attempt surfaces through `take_socket_error`; on success the RFC 1928 reply carries the
+    /// bound address and the connection enters `State::Relay`.
+    fn finish_connect(&mut self) -> Result<()> {
+        if let Err(e) = peer_mut(&mut self.peer).take_socket_error() {
+            try!(self.write_reply(reply_for_connect_error(&e), unspecified_addr()));
+            self.state = State::Closed;
+            return Ok(());
+        }
+        let bound = try!(peer_mut(&mut self.peer).local_addr());
+        try!(self.write_reply(Reply::NOERR, bound));
+        self.state = State::Relay;
+        Ok(())
+    }
+
+    /// Binds a relay socket for a `UDP ASSOCIATE` request, registers it in the event loop beside
+    /// the control connection, and replies with its bound address before entering
+    /// `State::UdpAssociate`. The relay socket lives only as long as this control connection
+    /// (RFC 1928 §7): it is dropped together with the `Connection`, closing the association.
+    fn associate_udp(&mut self, event_loop: &mut EventLoop<SocksServer>) -> Result<()> {
+        let udp = match UdpSocket::bound(&unspecified_addr()) {
+            Ok(sock) => sock,
+            Err(e) => {
+                try!(self.write_reply(reply_for_connect_error(&e), unspecified_addr()));
+                self.state = State::Closed;
+                return Ok(());
+            }
+        };
+
+        let udp_token = Token(self.token.0 + UDP_TOKEN_OFFSET);
+        try!(event_loop.register(&udp, udp_token, EventSet::readable(), PollOpt::edge()));
+        let bound = try!(udp.local_addr());
+        self.udp = Some(udp);
+        self.udp_token = Some(udp_token);
+
+        try!(self.write_reply(Reply::NOERR, bound));
+        self.state = State::UdpAssociate;
+        Ok(())
+    }
+
+    /// Drains every datagram currently queued on the relay socket, forwarding each in the
+    /// appropriate direction. A datagram from the client's learned source address is unwrapped and
+    /// sent on to its decoded destination; one from anywhere else is a reply to be wrapped back to
+    /// the client.
+    fn relay_udp(&mut self) -> Result<()> {
+        let mut datagram = [0u8; UDP_DATAGRAM_SIZE];
+        loop {
+            let (len, src) = {
+                let sock = self.udp.as_ref().expect("UDP relay entered without a bound socket");
+                match try!(sock.recv_from(&mut datagram)) {
+                    None => return Ok(()),  // No more datagrams are ready.
+                    Some(pair) => pair,
+                }
+            };
+            try!(self.forward_datagram(&datagram[..len], src));
+        }
+    }
+
+    /// Routes a single relayed datagram. The client's source address is learned lazily from its
+    /// first datagram; subsequent datagrams from that address are forwarded outward, while all
+    /// others are treated as replies and wrapped back to the client.
+    fn forward_datagram(&mut self, datagram: &[u8], src: SocketAddr) -> Result<()> {
+        let from_client = match self.udp_client {
+            Some(client) => src == client,
+            None => {
+                self.udp_client = Some(src);
+                true
+            }
+        };
+
+        let sock = self.udp.as_ref().expect("UDP relay entered without a bound socket");
+        if from_client {
+            // A malformed or fragmented datagram is dropped, not fatal: a stray bad packet must not
+            // tear down the whole association (RFC 1928 rejects `FRAG != 0`, it does not close).
+            let header = match UdpRequest::try_new(datagram) {
+                Ok(Some(header)) => header,
+                Ok(None) | Err(_) => return Ok(()),
+            };
+            let dest = match resolve_dest(&header.dest, header.port) {
+                Some(addr) => addr,
+                None => return Ok(()),  // Silently drop datagrams we cannot route.
+            };
+            try!(sock.send_to(&datagram[header.header_len ..], &dest));
+        } else {
+            let client = self.udp_client.expect("reply observed before the client's first datagram");
+            let wrapped = encode_udp_reply(&src, datagram);
+            try!(sock.send_to(&wrapped, &client));
+        }
+        Ok(())
+    }
+
+    /// Pumps bytes in both directions, honouring readable/writable readiness and half-close.
+    fn relay(&mut self, _event_loop: &mut EventLoop<SocksServer>, token: Token, events: EventSet)
+             -> Result<()> {
+        let is_client = token == self.token;
+
+        if events.is_readable() {
+            if is_client {
+                try!(pump_read(&mut self.client, &mut self.to_peer, &mut self.client_done));
+            } else {
+                try!(pump_read(peer_mut(&mut self.peer), &mut self.to_client, &mut self.peer_done));
+            }
+        }
+
+        // Always attempt to flush both directions: a readable event on one half may have produced
+        // bytes destined for the other, and a writable event lets us drain a backlog.
+        try!(pump_write(&mut self.client, &mut self.to_client));
+        try!(pump_write(peer_mut(&mut self.peer), &mut self.to_peer));
+
+        // Half-close: once a side has hit EOF and its pending bytes are flushed, shut the opposite
+        // socket's write half so the other end also observes the EOF.
+        if self.client_done && self.to_peer.is_empty() {
+            let _ = peer_mut(&mut self.peer).shutdown(::std::net::Shutdown::Write);
+        }
+        if self.peer_done && self.to_client.is_empty() {
+            let _ = self.client.shutdown(::std::net::Shutdown::Write);
+        }
+
+        if self.client_done && self.peer_done && self.to_peer.is_empty() && self.to_client.is_empty() {
+            self.state = State::Closed;
+        }
+        Ok(())
+    }
+
+    /// Appends any bytes currently readable on the client socket to `buf`, returning the number of
+    /// bytes read. `0` is returned both when the socket merely blocked and when the client has
+    /// closed it; EOF is not reported separately.
+    fn fill_from_client(&mut self) -> Result<usize> {
+        let mut done = false;
+        let before = self.buf.len();
+        try!(pump_read(&mut self.client, &mut self.buf, &mut done));
+        Ok(self.buf.len() - before)
+    }
+
+    /// Chooses an authentication method from those the client offered, or `None` when none is
+    /// acceptable. When credentials are configured, `PASSWD` is the only acceptable method, so a
+    /// client that does not offer it is rejected rather than granted unauthenticated access;
+    /// otherwise `NONE` is selected, but only when the client actually advertised it.
+    fn select_method(&self, offered: &AuthMethodsSet) -> Option<AuthMethod> {
+        if !self.credentials.is_empty() {
+            if offered.contains(&AuthMethod::PASSWD) {
+                Some(AuthMethod::PASSWD)
+            } else {
+                None
+            }
+        } else if offered.contains(&AuthMethod::NONE) {
+            Some(AuthMethod::NONE)
+        } else {
+            None
+        }
+    }
+
+    /// Sends the two-octet method-selection reply (VER, METHOD) to the client.
+    fn write_method(&mut self, method: AuthMethod) -> Result<()> {
+        try!(self.client.write_all(&[SocksVersion::SOCKS5 as u8, method as u8]));
+        Ok(())
+    }
+
+    /// Returns `true` iff the offered credentials match an entry in the configured store.
+    fn credentials_match(&self, creds: &UserPassMessage) -> bool {
+        match self.credentials.get(&creds.username) {
+            Some(password) => *password == creds.password,
+            None => false,
+        }
+    }
+
+    /// Serializes and sends a reply with the given bound address on the client socket, choosing the
+    /// RFC 1928 format or, for a SOCKS 4/4a client, the 8-octet v4 format.
+    fn write_reply(&mut self, reply: Reply, bound: SocketAddr) -> Result<()> {
+        if self.socks4 {
+            return self.write_socks4_reply(reply, bound);
+        }
+        let mut out = Vec::with_capacity(22);
+        out.push(SocksVersion::SOCKS5 as u8);
+        out.push(reply as u8);
+        out.push(0x00);  // RSV
+        match bound {
+            SocketAddr::V4(v4) => {
+                out.push(0x01);  // ATYP: IPv4
+                out.extend(v4.ip().octets().iter().cloned());
+                push_port(&mut out, v4.port());
+            }
+            SocketAddr::V6(v6) => {
+                out.push(0x04);  // ATYP: IPv6
+                out.extend(v6.ip().octets().iter().cloned());
+                push_port(&mut out, v6.port());
+            }
+        }
+        try!(self.client.write_all(&out));
+        Ok(())
+    }
+
+    /// Serializes and sends an 8-octet SOCKS 4 reply: a null version octet, a result code
+    /// (`0x5A` granted, `0x5B` rejected), and the bound port and IPv4 address. A non-`NOERR` reply
+    /// becomes a rejection; a v6 bound address (which the v4 format cannot express) is reported as
+    /// zeros.
+    fn write_socks4_reply(&mut self, reply: Reply, bound: SocketAddr) -> Result<()> {
+        let granted = match reply {
+            Reply::NOERR => 0x5A,
+            _ => 0x5B,
+        };
+        let (port, ip) = match bound {
+            SocketAddr::V4(v4) => (v4.port(), v4.ip().octets()),
+            SocketAddr::V6(_)  => (0, [0, 0, 0, 0]),
+        };
+        let mut out = Vec::with_capacity(8);
+        out.push(0x00);  // VN
+        out.push(granted);
+        push_port(&mut out, port);
+        out.extend(ip.iter().cloned());
+        try!(self.client.write_all(&out));
+        Ok(())
+    }
+}
+
+
+/// Reads all immediately-available bytes off of `sock` into `sink`. Sets `done` when the peer has
+/// closed the connection (a read of length 0).
+fn pump_read(sock: &mut TcpStream, sink: &mut Vec<u8>, done: &mut bool) -> IoResult<()> {
+    let mut chunk = [0u8; RELAY_CHUNK_SIZE];
+    loop {
+        match sock.read(&mut chunk) {
+            Ok(0) => { *done = true; return Ok(()); }
+            Ok(n) => sink.extend(chunk[..n].iter().cloned()),
+            Err(ref e) if e.kind() == WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Writes as much of `src` to `sock` as the socket will currently accept, draining the written
+/// prefix from `src`.
+fn pump_write(sock: &mut TcpStream, src: &mut Vec<u8>) -> IoResult<()> {
+    let mut written = 0;
+    while written < src.len() {
+        match sock.write(&src[written..]) {
+            Ok(0) => break,
+            Ok(n) => written += n,
+            Err(ref e) if e.kind() == WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+    src.drain(..written);
+    Ok(())
+}
+
+/// Borrows the peer socket, panicking if it is reached before a relay target has been opened.
+fn peer_mut(peer: &mut Option<TcpStream>) -> &mut TcpStream {
+    peer.as_mut().expect("relay stage entered without an open peer socket")
+}
+
+#[inline]
+fn push_port(out: &mut Vec<u8>, port: u16) {
+    out.push((port >> 8) as u8);
+    out.push(port as u8);
+}
+
+#[inline]
+fn unspecified_addr() -> SocketAddr {
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0))
+}
+
+/// Maps a failure to open the outbound socket onto the most specific RFC 1928 reply code.
+fn reply_for_connect_error(e: &Error) -> Reply {
+    use std::io::ErrorKind::*;
+    match e.kind() {
+        ConnectionRefused => Reply::CONNREF,
+        _ => Reply::HOSTUNREACH,
+    }
+}
+
+/// Resolves a parsed `DestAddr` and port into a concrete `SocketAddr`. Literal addresses are
+/// trivial; a domain name is resolved through the system resolver, yielding the first result.
+fn resolve_dest(dest: &DestAddr, port: u16) -> Option<SocketAddr> {
+    use std::net::ToSocketAddrs;
+    match *dest {
+        DestAddr::IPv4(ip) => Some(SocketAddr::V4(SocketAddrV4::new(ip, port))),
+        DestAddr::IPv6(ip) => Some(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0))),
+        DestAddr::DomainName(ref name) => {
+            match (name.as_str(), port).to_socket_addrs() {
+                Ok(mut addrs) => addrs.next(),
+                Err(_) => None,
+            }
         }
     }
 }
@@ -66,6 +615,18 @@ enum State {
     /// addresses, and return one or more reply messages, as appropriate for the request type.
     WriteReplies,
 
+    /// The outbound socket has been opened but its non-blocking connect has not yet resolved; the
+    /// reply is withheld until the peer token signals writability.
+    Connecting,
+
+    /// The request has been served and the connection now pumps bytes between the client and the
+    /// outbound peer socket until both directions have reached EOF.
+    Relay,
+
+    /// A `UDP ASSOCIATE` request has been served and the connection now relays datagrams through
+    /// its bound UDP socket until the control connection closes.
+    UdpAssociate,
+
     /// The SOCKS server's connection to the client has been closed (for one of various reasons).
     Closed,
 }
@@ -86,11 +647,11 @@ impl AuthMethodsSet {
             Some(v) => *v,
         };
         let version = match SocksVersion::from_u8(version) {
-            None    => return Err(Error::new(InvalidData, "unknown socks version")),
+            None    => return Err(SocksError::UnknownVersion(version)),
             Some(v) => v,
         };
         match version {
-            SOCKS4 => Err(Error::new(InvalidData, "SOCKS v4 not supported")),
+            SOCKS4 => Err(SocksError::UnsupportedVersion(SOCKS4)),
             SOCKS5 => Ok(Some(SOCKS5)),
         }
     }
@@ -102,7 +663,7 @@ impl AuthMethodsSet {
             Some(n) => *n,
         };
         match nmethods {
-            0 => Err(Error::new(InvalidData, "`nmethods` cannot be 0")),
+            0 => Err(SocksError::ZeroMethods),
             n => Ok(Some(n)),
         }
     }
@@ -150,7 +711,7 @@ impl AuthMethodsSet {
     #[inline]
     fn add_by_id(&mut self, method_id: u8) -> Result<()> {
         let method = match AuthMethod::from_u8(method_id) {
-            None    => return Err(Error::new(InvalidData, "unknown auth method")),
+            None    => return Err(SocksError::UnknownAuthMethod(method_id)),
             Some(m) => m,
         };
         self.add(method);
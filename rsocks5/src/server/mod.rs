@@ -1,9 +1,17 @@
 mod connection;
 
-use mio::{Handler, EventLoop, Token, EventSet};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use mio::{Handler, EventLoop, EventSet, PollOpt, Token};
+use mio::tcp::TcpListener;
 use mio::util::Slab;
 
-use self::connection::Connection;
+use self::connection::{Connection, PEER_TOKEN_OFFSET, UDP_TOKEN_OFFSET};
+
+/// The shared username/password store consulted during RFC 1929 sub-negotiation. Cloned (cheaply,
+/// via `Rc`) into each `Connection`. An empty map disables username/password authentication.
+pub type Credentials = Rc<HashMap<String, String>>;
 
 
 /**
@@ -17,9 +25,60 @@ pub const GENERICBUFSIZE: usize = 4096;
 pub const IPPORT_RESERVED: u16 = 1024;
 pub const SOCKS_DEF_PORT: u16 = 1080;
 
+/// The token on which the listening socket itself is registered. Accepted connections are slotted
+/// into the `Slab` at higher tokens.
+const LISTENER: Token = Token(0);
+
 
 struct SocksServer {
+    listener: TcpListener,
     connections: Slab<Connection>,
+    credentials: Credentials,
+}
+
+impl SocksServer {
+    /// Maps an event-loop token to the slab index of its owning connection. Client tokens index
+    /// the slab directly; peer tokens are offset by `PEER_TOKEN_OFFSET` and UDP relay tokens by
+    /// `UDP_TOKEN_OFFSET` (see `connection`).
+    fn owner(token: Token) -> Token {
+        if token.0 >= UDP_TOKEN_OFFSET {
+            Token(token.0 - UDP_TOKEN_OFFSET)
+        } else if token.0 >= PEER_TOKEN_OFFSET {
+            Token(token.0 - PEER_TOKEN_OFFSET)
+        } else {
+            token
+        }
+    }
+
+    /// Accepts any pending client connections and registers them in the event loop.
+    fn accept(&mut self, event_loop: &mut EventLoop<SocksServer>) {
+        loop {
+            match self.listener.accept() {
+                Ok(Some((sock, _addr))) => {
+                    let credentials = self.credentials.clone();
+                    let token = match self.connections.insert_with(|token| Connection::new(token, sock, credentials.clone())) {
+                        Some(token) => token,
+                        None => {
+                            info!("connection slab is full; dropping accepted socket");
+                            break;
+                        }
+                    };
+                    let client = self.connections[token].client();
+                    if let Err(e) = event_loop.register(client, token,
+                                                        EventSet::readable() | EventSet::writable(),
+                                                        PollOpt::edge()) {
+                        info!("failed to register accepted connection: {}", e);
+                        self.connections.remove(token);
+                    }
+                }
+                Ok(None) => break,  // No more connections are waiting to be accepted.
+                Err(e) => {
+                    info!("error while accepting a connection: {}", e);
+                    break;
+                }
+            }
+        }
+    }
 }
 
 impl Handler for SocksServer {
@@ -27,13 +86,25 @@ impl Handler for SocksServer {
     type Message = ();
 
     fn ready(&mut self, event_loop: &mut EventLoop<SocksServer>, token: Token, events: EventSet) {
-        let connection = match self.connections.get_mut(token) {
-            None => {
-                info!("`Server::ready()` called with token not associated with a connection");
-                return;
-            },
-            Some(conn) => conn,
-        };
-        connection.ready(event_loop, events);
+        if token == LISTENER {
+            self.accept(event_loop);
+            return;
+        }
+
+        let owner = Self::owner(token);
+        {
+            let connection = match self.connections.get_mut(owner) {
+                None => {
+                    info!("`Server::ready()` called with token not associated with a connection");
+                    return;
+                },
+                Some(conn) => conn,
+            };
+            connection.ready(event_loop, token, events);
+        }
+
+        if self.connections[owner].is_closed() {
+            self.connections.remove(owner);
+        }
     }
 }